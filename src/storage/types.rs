@@ -22,11 +22,32 @@ pub struct MvccInfo {
     pub values: Vec<(TimeStamp, Value)>,
 }
 
+/// A single bounded page of a key's mvcc history.
+///
+/// Unlike [`MvccInfo`], which collects a key's entire version history into
+/// memory in one shot, `MvccInfoChunk` caps the number of versions returned
+/// and carries a `next_cursor` so callers can keep paging through hot keys
+/// with very long histories without risking an OOM.
+#[derive(Debug, Default)]
+pub struct MvccInfoChunk {
+    pub lock: Option<Lock>,
+    /// commit_ts and write, capped to the request's version limit
+    pub writes: Vec<(TimeStamp, Write)>,
+    /// start_ts and value, capped to the request's version limit
+    pub values: Vec<(TimeStamp, Value)>,
+    /// The start_ts to resume from on the next page, or `None` once the
+    /// key's whole history has been drained.
+    pub next_cursor: Option<TimeStamp>,
+}
+
 /// A row mutation.
 #[derive(Debug, Clone)]
 pub enum Mutation {
     /// Put `Value` into `Key`, overwriting any existing value.
-    Put((Key, Value, Option<Vec<RawKey>>)),
+    ///
+    /// `ttl` is the time-to-live in seconds after which the key expires, or
+    /// `None`/`Some(0)` for no expiry.
+    Put((Key, Value, Option<Vec<RawKey>>, Option<u64>)),
     /// Delete `Key`.
     Delete((Key, Option<Vec<RawKey>>)),
     /// Set a lock on `Key`.
@@ -34,25 +55,39 @@ pub enum Mutation {
     /// Put `Value` into `Key` if `Key` does not yet exist.
     ///
     /// Returns [`KeyError::AlreadyExists`](kvproto::kvrpcpb::KeyError::AlreadyExists) if the key already exists.
-    Insert((Key, Value, Option<Vec<RawKey>>)),
+    ///
+    /// `ttl` is the time-to-live in seconds after which the key expires, or
+    /// `None`/`Some(0)` for no expiry.
+    Insert((Key, Value, Option<Vec<RawKey>>, Option<u64>)),
+    /// Acquire a pessimistic lock on `Key`, without writing a value.
+    PessimisticLock((Key, Option<Vec<RawKey>>)),
 }
 
 impl Mutation {
     pub fn key(&self) -> &Key {
         match self {
-            Mutation::Put((ref key, _, _)) => key,
+            Mutation::Put((ref key, ..)) => key,
             Mutation::Delete((ref key, _)) => key,
             Mutation::Lock((ref key, _)) => key,
-            Mutation::Insert((ref key, _, _)) => key,
+            Mutation::Insert((ref key, ..)) => key,
+            Mutation::PessimisticLock((ref key, _)) => key,
         }
     }
 
-    pub fn into_inner(self) -> (Key, Option<Value>, Option<Vec<RawKey>>) {
+    /// Consumes the mutation, returning its key, value, secondary keys, and
+    /// TTL. As with [`Mutation::ttl`], a TTL of `Some(0)` normalizes to
+    /// `None` so the write path only ever sees one "no expiry" representation.
+    pub fn into_inner(self) -> (Key, Option<Value>, Option<Vec<RawKey>>, Option<u64>) {
         match self {
-            Mutation::Put((key, value, secondary_keys)) => (key, Some(value), secondary_keys),
-            Mutation::Delete((key, secondary_keys)) => (key, None, secondary_keys),
-            Mutation::Lock((key, secondary_keys)) => (key, None, secondary_keys),
-            Mutation::Insert((key, value, secondary_keys)) => (key, Some(value), secondary_keys),
+            Mutation::Put((key, value, secondary_keys, ttl)) => {
+                (key, Some(value), secondary_keys, ttl.filter(|&t| t != 0))
+            }
+            Mutation::Delete((key, secondary_keys)) => (key, None, secondary_keys, None),
+            Mutation::Lock((key, secondary_keys)) => (key, None, secondary_keys, None),
+            Mutation::Insert((key, value, secondary_keys, ttl)) => {
+                (key, Some(value), secondary_keys, ttl.filter(|&t| t != 0))
+            }
+            Mutation::PessimisticLock((key, secondary_keys)) => (key, None, secondary_keys, None),
         }
     }
 
@@ -62,6 +97,25 @@ impl Mutation {
             _ => false,
         }
     }
+
+    pub fn is_pessimistic_lock(&self) -> bool {
+        match self {
+            Mutation::PessimisticLock(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The mutation's time-to-live in seconds, or `None` if the key never
+    /// expires. A `Put`/`Insert` constructed with `Some(0)` normalizes to
+    /// `None` here, so callers only ever need to handle one "no expiry"
+    /// representation. Every other variant returns `None`.
+    pub fn ttl(&self) -> Option<u64> {
+        match self {
+            Mutation::Put((_, _, _, ttl)) => ttl.filter(|&t| t != 0),
+            Mutation::Insert((_, _, _, ttl)) => ttl.filter(|&t| t != 0),
+            _ => None,
+        }
+    }
 }
 
 /// Represents the status of a transaction.
@@ -69,6 +123,8 @@ impl Mutation {
 pub enum TxnStatus {
     /// The txn was already rolled back before.
     Rollbacked,
+    /// The txn's pessimistic lock was rolled back.
+    PessimisticRollbacked,
     /// The txn is just rolled back due to expiration.
     TtlExpire,
     /// The txn is just rolled back due to lock not exist.
@@ -101,9 +157,11 @@ pub enum StorageCallback {
     Booleans(Callback<Vec<Result<()>>>),
     BatchBooleans(BatchCallback<Vec<Result<()>>>),
     MvccInfoByKey(Callback<MvccInfo>),
+    MvccInfoByKeyStream(Callback<MvccInfoChunk>),
     MvccInfoByStartTs(Callback<Option<(Key, MvccInfo)>>),
     Locks(Callback<Vec<LockInfo>>),
     TxnStatus(Callback<TxnStatus>),
+    PessimisticLockRes(Callback<Vec<Result<()>>>),
 }
 
 /// Process result of a command.
@@ -111,70 +169,215 @@ pub enum ProcessResult {
     Res,
     MultiRes { results: Vec<Result<()>> },
     MvccKey { mvcc: MvccInfo },
+    MvccKeyStream { mvcc: MvccInfoChunk },
     MvccStartTs { mvcc: Option<(Key, MvccInfo)> },
     Locks { locks: Vec<LockInfo> },
     TxnStatus { txn_status: TxnStatus },
+    PessimisticLockRes { results: Vec<Result<()>> },
     NextCommand { cmd: Command },
     Failed { err: StorageError },
 }
 
+/// Builds the error delivered (and returned) when a `ProcessResult` doesn't
+/// match the shape the waiting `StorageCallback` expects.
+fn callback_mismatch(case: &'static str) -> StorageError {
+    StorageError::CallbackMismatch { case }
+}
+
 impl StorageCallback {
     /// Delivers the process result of a command to the storage callback.
-    pub fn execute(self, pr: ProcessResult) {
+    ///
+    /// Returns `Err` instead of panicking when `pr` doesn't match the shape
+    /// this callback expects, so a single scheduler bug degrades to a failed
+    /// command rather than aborting the process.
+    pub fn execute(self, pr: ProcessResult) -> Result<()> {
         match self {
             StorageCallback::Boolean(cb) => match pr {
-                ProcessResult::Res => cb(Ok(())),
-                ProcessResult::Failed { err } => cb(Err(err)),
-                _ => panic!("process result mismatch"),
+                ProcessResult::Res => {
+                    cb(Ok(()));
+                    Ok(())
+                }
+                ProcessResult::Failed { err } => {
+                    cb(Err(err));
+                    Ok(())
+                }
+                _ => {
+                    cb(Err(callback_mismatch("Boolean")));
+                    Err(callback_mismatch("Boolean"))
+                }
             },
             StorageCallback::Booleans(cb) => match pr {
-                ProcessResult::MultiRes { results } => cb(Ok(results)),
-                ProcessResult::Failed { err } => cb(Err(err)),
-                _ => panic!("process result mismatch"),
+                ProcessResult::MultiRes { results } => {
+                    cb(Ok(results));
+                    Ok(())
+                }
+                ProcessResult::Failed { err } => {
+                    cb(Err(err));
+                    Ok(())
+                }
+                _ => {
+                    cb(Err(callback_mismatch("Booleans")));
+                    Err(callback_mismatch("Booleans"))
+                }
             },
             StorageCallback::MvccInfoByKey(cb) => match pr {
-                ProcessResult::MvccKey { mvcc } => cb(Ok(mvcc)),
-                ProcessResult::Failed { err } => cb(Err(err)),
-                _ => panic!("process result mismatch"),
+                ProcessResult::MvccKey { mvcc } => {
+                    cb(Ok(mvcc));
+                    Ok(())
+                }
+                ProcessResult::Failed { err } => {
+                    cb(Err(err));
+                    Ok(())
+                }
+                _ => {
+                    cb(Err(callback_mismatch("MvccInfoByKey")));
+                    Err(callback_mismatch("MvccInfoByKey"))
+                }
+            },
+            StorageCallback::MvccInfoByKeyStream(cb) => match pr {
+                ProcessResult::MvccKeyStream { mvcc } => {
+                    cb(Ok(mvcc));
+                    Ok(())
+                }
+                ProcessResult::Failed { err } => {
+                    cb(Err(err));
+                    Ok(())
+                }
+                _ => {
+                    cb(Err(callback_mismatch("MvccInfoByKeyStream")));
+                    Err(callback_mismatch("MvccInfoByKeyStream"))
+                }
             },
             StorageCallback::MvccInfoByStartTs(cb) => match pr {
-                ProcessResult::MvccStartTs { mvcc } => cb(Ok(mvcc)),
-                ProcessResult::Failed { err } => cb(Err(err)),
-                _ => panic!("process result mismatch"),
+                ProcessResult::MvccStartTs { mvcc } => {
+                    cb(Ok(mvcc));
+                    Ok(())
+                }
+                ProcessResult::Failed { err } => {
+                    cb(Err(err));
+                    Ok(())
+                }
+                _ => {
+                    cb(Err(callback_mismatch("MvccInfoByStartTs")));
+                    Err(callback_mismatch("MvccInfoByStartTs"))
+                }
             },
             StorageCallback::Locks(cb) => match pr {
-                ProcessResult::Locks { locks } => cb(Ok(locks)),
-                ProcessResult::Failed { err } => cb(Err(err)),
-                _ => panic!("process result mismatch"),
+                ProcessResult::Locks { locks } => {
+                    cb(Ok(locks));
+                    Ok(())
+                }
+                ProcessResult::Failed { err } => {
+                    cb(Err(err));
+                    Ok(())
+                }
+                _ => {
+                    cb(Err(callback_mismatch("Locks")));
+                    Err(callback_mismatch("Locks"))
+                }
             },
             StorageCallback::TxnStatus(cb) => match pr {
-                ProcessResult::TxnStatus { txn_status } => cb(Ok(txn_status)),
-                ProcessResult::Failed { err } => cb(Err(err)),
-                _ => panic!("process result mismatch"),
+                ProcessResult::TxnStatus { txn_status } => {
+                    cb(Ok(txn_status));
+                    Ok(())
+                }
+                ProcessResult::Failed { err } => {
+                    cb(Err(err));
+                    Ok(())
+                }
+                _ => {
+                    cb(Err(callback_mismatch("TxnStatus")));
+                    Err(callback_mismatch("TxnStatus"))
+                }
+            },
+            StorageCallback::PessimisticLockRes(cb) => match pr {
+                ProcessResult::PessimisticLockRes { results } => {
+                    cb(Ok(results));
+                    Ok(())
+                }
+                ProcessResult::Failed { err } => {
+                    cb(Err(err));
+                    Ok(())
+                }
+                _ => {
+                    cb(Err(callback_mismatch("PessimisticLockRes")));
+                    Err(callback_mismatch("PessimisticLockRes"))
+                }
             },
-            _ => panic!("callback type mismatch"),
+            // Only `BatchBoolean`/`BatchBooleans` fall through to here, and
+            // their callback expects a keyed `Vec<(u64, Result<_>)>`, not the
+            // single value this function deals in — there's no id to key a
+            // best-effort error by, so the callback genuinely cannot be
+            // invoked from this entry point. Reaching this arm means a batch
+            // command was dispatched through `execute` instead of
+            // `execute_batch`, which is a scheduler bug; the waiting client
+            // will time out rather than see an explicit error, since a
+            // structural type mismatch (not just a `ProcessResult` mismatch)
+            // blocks delivery. Route batch callbacks through `execute_batch`.
+            StorageCallback::BatchBoolean(_) | StorageCallback::BatchBooleans(_) => {
+                debug_assert!(
+                    false,
+                    "StorageCallback::execute called with a batch callback; use execute_batch"
+                );
+                Err(callback_mismatch("unknown"))
+            }
         }
     }
 
-    pub fn execute_batch(&mut self, pr: Vec<(u64, ProcessResult)>) {
+    pub fn execute_batch(&mut self, pr: Vec<(u64, ProcessResult)>) -> Result<()> {
         match self {
-            StorageCallback::BatchBoolean(cb) => cb(pr
-                .into_iter()
-                .map(|(id, r)| match r {
-                    ProcessResult::Res => (id, Ok(())),
-                    ProcessResult::Failed { err } => (id, Err(err)),
-                    _ => panic!("process result mismatch"),
-                })
-                .collect()),
-            StorageCallback::BatchBooleans(cb) => cb(pr
-                .into_iter()
-                .map(|(id, r)| match r {
-                    ProcessResult::MultiRes { results } => (id, Ok(results)),
-                    ProcessResult::Failed { err } => (id, Err(err)),
-                    _ => panic!("process result mismatch"),
-                })
-                .collect()),
-            _ => panic!("callback type mismatch"),
+            StorageCallback::BatchBoolean(cb) => {
+                let mut mismatch = false;
+                cb(pr
+                    .into_iter()
+                    .map(|(id, r)| match r {
+                        ProcessResult::Res => (id, Ok(())),
+                        ProcessResult::Failed { err } => (id, Err(err)),
+                        _ => {
+                            mismatch = true;
+                            (id, Err(callback_mismatch("BatchBoolean")))
+                        }
+                    })
+                    .collect());
+                if mismatch {
+                    return Err(callback_mismatch("BatchBoolean"));
+                }
+                Ok(())
+            }
+            StorageCallback::BatchBooleans(cb) => {
+                let mut mismatch = false;
+                cb(pr
+                    .into_iter()
+                    .map(|(id, r)| match r {
+                        ProcessResult::MultiRes { results } => (id, Ok(results)),
+                        ProcessResult::Failed { err } => (id, Err(err)),
+                        _ => {
+                            mismatch = true;
+                            (id, Err(callback_mismatch("BatchBooleans")))
+                        }
+                    })
+                    .collect());
+                if mismatch {
+                    return Err(callback_mismatch("BatchBooleans"));
+                }
+                Ok(())
+            }
+            // Every non-batch callback falls through to here. Each expects a
+            // single value, not the keyed `Vec<(u64, Result<_>)>` this
+            // function produces, so — as in `execute`'s equivalent arm —
+            // there's no way to invoke it with even a best-effort error.
+            // Reaching this arm means a single-command callback was
+            // dispatched through `execute_batch` instead of `execute`, which
+            // is a scheduler bug; the waiting client will time out rather
+            // than see an explicit error. Route single-command callbacks
+            // through `execute`.
+            _ => {
+                debug_assert!(
+                    false,
+                    "StorageCallback::execute_batch called with a non-batch callback; use execute"
+                );
+                Err(callback_mismatch("unknown"))
+            }
         }
     }
 }